@@ -13,6 +13,10 @@ mod proto {
     include!(concat!(env!("OUT_DIR"), "/header.proxy.l5d.io.rs"));
 }
 
+mod mux;
+
+pub use self::mux::{Multiplexer, Substream};
+
 #[derive(Clone, Debug)]
 pub struct Header {
     /// The target port.
@@ -22,11 +26,257 @@ pub struct Header {
     pub name: Option<Name>,
 }
 
-#[derive(Clone, Debug, Default)]
-pub struct DetectHeader(());
+#[derive(Clone, Debug)]
+pub struct DetectHeader {
+    max_message_len: usize,
+}
+
+impl Default for DetectHeader {
+    fn default() -> Self {
+        Self {
+            max_message_len: MESSAGE_LENGTH_MAX,
+        }
+    }
+}
+
+impl DetectHeader {
+    /// Sets the maximum permitted size, in bytes, of the connection header
+    /// message.
+    ///
+    /// Headers that declare a larger length are rejected before any buffer
+    /// is reserved for them.
+    pub fn with_max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+}
 
 const PREFACE: &[u8] = b"proxy.l5d.io/connect\r\n\r\n";
-const PREFACE_LEN: usize = PREFACE.len() + 4;
+
+/// The size, in bytes, of an encoded [`FrameHeader`].
+///
+/// `length: u32` + `stream_id: u32` + `type_: u8` + `flags: u8`.
+const FRAME_HEADER_LEN: usize = 10;
+
+/// The maximum size, in bytes, of a single framed message.
+///
+/// This bounds the allocation a peer can force via a single frame's
+/// `length` field.
+pub const MESSAGE_LENGTH_MAX: usize = 4 * 1024 * 1024;
+
+/// A frame carries an initial request, a response, or a chunk of streamed
+/// data.
+pub mod frame_type {
+    /// The initial frame of a logical stream, carrying a [`Header`][super::Header].
+    pub const REQUEST: u8 = 0x1;
+
+    /// A response to a `REQUEST` frame.
+    pub const RESPONSE: u8 = 0x2;
+
+    /// A chunk of streamed payload data.
+    pub const DATA: u8 = 0x3;
+}
+
+/// Bits set on [`FrameHeader::flags`] to signal half-close/open state for a
+/// stream, independent of the frame's payload.
+pub mod flags {
+    /// The sender will not send any more frames for this stream.
+    pub const REMOTE_CLOSED: u8 = 0b001;
+
+    /// This frame opens a new logical stream.
+    pub const REMOTE_OPEN: u8 = 0b010;
+
+    /// The frame carries no payload bytes.
+    pub const NO_DATA: u8 = 0b100;
+}
+
+/// The HTTP/2 client connection preface.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The TLS record type for a Handshake record (e.g. a ClientHello).
+const TLS_RECORD_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// The major byte of the legacy TLS record-layer version.
+const TLS_LEGACY_VERSION_MAJOR: u8 = 0x03;
+
+/// The most bytes buffered before probing for a known preface; large
+/// enough to disambiguate all protocols `DetectProtocol` knows about.
+const DETECT_BUF_LEN: usize = {
+    // `const fn` max isn't stable for usize on our MSRV, so compare by hand.
+    if PREFACE.len() > H2_PREFACE.len() {
+        PREFACE.len()
+    } else {
+        H2_PREFACE.len()
+    }
+};
+
+/// A protocol identified by [`DetectProtocol`] from a connection's leading
+/// bytes.
+#[derive(Clone, Debug)]
+pub enum Protocol {
+    /// The `proxy.l5d.io/connect` preface, with its header already decoded.
+    Connect(Header),
+
+    /// The HTTP/2 client connection preface.
+    Http2,
+
+    /// A TLS ClientHello.
+    Tls,
+
+    /// None of the known prefaces matched. The buffered bytes are left
+    /// untouched for the caller to interpret.
+    Opaque,
+}
+
+/// Probes a connection's leading bytes against several known prefaces —
+/// the l5d connect header, the HTTP/2 client connection preface, and a TLS
+/// ClientHello signature — so that callers can make one detection call
+/// instead of chaining per-protocol detectors.
+///
+/// Bytes that don't belong to a recognized preface are left in the
+/// caller's buffer exactly as read, the same way [`DetectHeader`] leaves
+/// non-header bytes intact.
+#[derive(Clone, Debug, Default)]
+pub struct DetectProtocol(DetectHeader);
+
+impl DetectProtocol {
+    /// Sets the maximum permitted size, in bytes, of a `Connect` header
+    /// message. See [`DetectHeader::with_max_message_len`].
+    pub fn with_max_message_len(self, max_message_len: usize) -> Self {
+        Self(self.0.with_max_message_len(max_message_len))
+    }
+}
+
+#[async_trait::async_trait]
+impl Detect for DetectProtocol {
+    type Protocol = Protocol;
+
+    #[inline]
+    async fn detect<I: io::AsyncRead + Send + Unpin + 'static>(
+        &self,
+        io: &mut I,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Protocol>, Error> {
+        // Buffer enough bytes to disambiguate between all known prefaces,
+        // stopping early once at least two bytes have been read and they
+        // can't begin the connect or HTTP/2 preface. If the connection
+        // closes first, whatever was read is still probed below and,
+        // failing a match, preserved as `Opaque`.
+        while buf.len() < DETECT_BUF_LEN
+            && (buf.len() < 2
+                || [PREFACE, H2_PREFACE]
+                    .iter()
+                    .any(|p| p.starts_with(&buf[..buf.len().min(p.len())])))
+        {
+            if io.read_buf(buf).await? == 0 {
+                break;
+            }
+        }
+
+        if buf.starts_with(PREFACE) {
+            return match Header::read_prefaced(io, buf, self.0.max_message_len).await? {
+                Some(header) => Ok(Some(Protocol::Connect(header))),
+                None => Ok(Some(Protocol::Opaque)),
+            };
+        }
+
+        if buf.starts_with(H2_PREFACE) {
+            return Ok(Some(Protocol::Http2));
+        }
+
+        if buf.len() >= 2
+            && buf[0] == TLS_RECORD_TYPE_HANDSHAKE
+            && buf[1] == TLS_LEGACY_VERSION_MAJOR
+        {
+            return Ok(Some(Protocol::Tls));
+        }
+
+        Ok(Some(Protocol::Opaque))
+    }
+}
+
+/// A fixed-size frame header, modeled on the ttrpc message header, that
+/// precedes each frame's payload once the connect preface has been
+/// consumed.
+///
+/// The wire format is 10 bytes, big-endian: `length: u32`, `stream_id:
+/// u32`, `type_: u8`, `flags: u8`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    /// The number of payload bytes following this header.
+    pub length: u32,
+
+    /// The logical stream this frame belongs to.
+    pub stream_id: u32,
+
+    /// One of the `frame_type` constants.
+    pub type_: u8,
+
+    /// A bitwise-or of `flags` constants.
+    pub flags: u8,
+}
+
+impl FrameHeader {
+    /// Encodes the frame header to a byte buffer.
+    #[inline]
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        if self.length as usize > MESSAGE_LENGTH_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Frame length exceeds MESSAGE_LENGTH_MAX",
+            )
+            .into());
+        }
+
+        buf.reserve(FRAME_HEADER_LEN);
+        buf.put_u32(self.length);
+        buf.put_u32(self.stream_id);
+        buf.put_u8(self.type_);
+        buf.put_u8(self.flags);
+
+        Ok(())
+    }
+
+    /// Reads a frame header from an I/O stream.
+    ///
+    /// On success, `buf` is reserved enough capacity to hold the frame's
+    /// payload, which the caller is responsible for reading.
+    #[inline]
+    pub async fn read<I: io::AsyncRead + Unpin + 'static>(
+        io: &mut I,
+        buf: &mut BytesMut,
+    ) -> io::Result<Self> {
+        while buf.len() < FRAME_HEADER_LEN {
+            if io.read_buf(buf).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before a full frame header was read",
+                ));
+            }
+        }
+
+        let mut hdr = buf.split_to(FRAME_HEADER_LEN);
+        let length = hdr.get_u32();
+        let stream_id = hdr.get_u32();
+        let type_ = hdr.get_u8();
+        let flags = hdr.get_u8();
+
+        if length as usize > MESSAGE_LENGTH_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Frame length exceeds MESSAGE_LENGTH_MAX",
+            ));
+        }
+        buf.reserve(length as usize);
+
+        Ok(Self {
+            length,
+            stream_id,
+            type_,
+            flags,
+        })
+    }
+}
 
 #[async_trait::async_trait]
 impl Detect for DetectHeader {
@@ -38,35 +288,34 @@ impl Detect for DetectHeader {
         io: &mut I,
         buf: &mut BytesMut,
     ) -> Result<Option<Header>, Error> {
-        let header = Header::read_prefaced(io, buf).await?;
+        let header = Header::read_prefaced(io, buf, self.max_message_len).await?;
         Ok(header)
     }
 }
 
 impl Header {
     /// Encodes the connection header to a byte buffer.
+    ///
+    /// The header message is carried as a `REQUEST` frame on `stream_id: 0`,
+    /// so it's framed the same way as any other traffic following the
+    /// preface.
     #[inline]
     pub fn encode_prefaced(&self, buf: &mut BytesMut) -> Result<(), Error> {
-        buf.reserve(PREFACE_LEN);
+        buf.reserve(PREFACE.len());
         buf.put(PREFACE);
 
-        debug_assert!(buf.capacity() >= 4);
-        // Safety: These bytes must be initialized below once the message has
-        // been encoded.
-        unsafe {
-            buf.advance_mut(4);
-        }
-
-        self.encode(buf)?;
+        let mut msg = BytesMut::new();
+        self.encode(&mut msg)?;
+        assert!(msg.len() <= std::u32::MAX as usize);
 
-        // Once the message length is known, we back-fill the length at the
-        // start of the buffer.
-        let len = buf.len() - PREFACE_LEN;
-        assert!(len <= std::u32::MAX as usize);
-        {
-            let mut buf = &mut buf[PREFACE.len()..PREFACE_LEN];
-            buf.put_u32(len as u32);
+        FrameHeader {
+            length: msg.len() as u32,
+            stream_id: 0,
+            type_: frame_type::REQUEST,
+            flags: flags::REMOTE_OPEN,
         }
+        .encode(buf)?;
+        buf.extend_from_slice(&msg);
 
         Ok(())
     }
@@ -90,15 +339,17 @@ impl Header {
     /// If the header is not present, the non-header bytes that were read are
     /// returned.
     ///
-    /// An I/O error is returned if the connection header is invalid.
+    /// An I/O error is returned if the connection header is invalid, or if
+    /// the frame following the preface isn't a `REQUEST`/`REMOTE_OPEN` frame.
     #[inline]
     async fn read_prefaced<I: io::AsyncRead + Unpin + 'static>(
         io: &mut I,
         buf: &mut BytesMut,
+        max_message_len: usize,
     ) -> io::Result<Option<Self>> {
-        // Read at least enough data to determine whether a connection header is
-        // present and, if so, how long it is.
-        while buf.len() < PREFACE_LEN {
+        // Read at least enough data to determine whether the connect
+        // preface is present.
+        while buf.len() < PREFACE.len() {
             if io.read_buf(buf).await? == 0 {
                 return Ok(None);
             }
@@ -110,19 +361,35 @@ impl Header {
         }
         buf.advance(PREFACE.len());
 
-        // Read the message length. If it is larger than our allowed buffer
-        // capacity, fail the connection.
-        let msg_len = buf.get_u32() as usize;
-        if msg_len > buf.capacity() + PREFACE_LEN {
+        // Reject a declared length that exceeds the configured maximum
+        // before `FrameHeader::read` reserves room for it, regardless of the
+        // buffer's current capacity, so a peer can't force a large
+        // `reserve` by declaring a huge length.
+        while buf.len() < FRAME_HEADER_LEN {
+            if io.read_buf(buf).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before a full frame header was read",
+                ));
+            }
+        }
+        let msg_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if msg_len > max_message_len {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                "Message length exceeds capacity",
+                "Message length exceeds the maximum allowed header size",
+            ));
+        }
+
+        // The header message is always carried as a single REQUEST frame.
+        let fh = FrameHeader::read(io, buf).await?;
+        if fh.type_ != frame_type::REQUEST || fh.flags & flags::REMOTE_OPEN == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Connection header must be a REQUEST/REMOTE_OPEN frame",
             ));
         }
 
-        // Free up parsed preface data and ensure there's enough capacity for
-        // the message.
-        buf.reserve(msg_len);
         while buf.len() < msg_len {
             if io.read_buf(buf).await? == 0 {
                 return Err(io::Error::new(
@@ -140,7 +407,7 @@ impl Header {
 
     // Decodes a protobuf message from the buffer.
     #[inline]
-    fn decode<B: Buf>(buf: B) -> io::Result<Option<Self>> {
+    pub(crate) fn decode<B: Buf>(buf: B) -> io::Result<Option<Self>> {
         let h = proto::Header::decode(buf)
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid header message"))?;
 
@@ -176,7 +443,7 @@ mod tests {
             std::io::Cursor::new(buf.freeze())
         };
         let mut buf = BytesMut::new();
-        let h = Header::read_prefaced(&mut rx, &mut buf)
+        let h = Header::read_prefaced(&mut rx, &mut buf, MESSAGE_LENGTH_MAX)
             .await
             .expect("decodes")
             .expect("decodes");
@@ -233,22 +500,29 @@ mod tests {
                 header.encode(&mut buf).expect("must encode");
                 buf.freeze()
             };
-            let len = {
-                let mut buf = BytesMut::with_capacity(4);
-                buf.put_u32(msg.len() as u32);
+            let fh = {
+                let mut buf = BytesMut::new();
+                FrameHeader {
+                    length: msg.len() as u32,
+                    stream_id: 0,
+                    type_: frame_type::REQUEST,
+                    flags: flags::REMOTE_OPEN,
+                }
+                .encode(&mut buf)
+                .expect("must encode");
                 buf.freeze()
             };
             tokio_test::io::Builder::new()
                 .read(b"proxy.l5d")
                 .read(b".io/connect")
                 .read(b"\r\n\r\n")
-                .read(len.as_ref())
+                .read(fh.as_ref())
                 .read(msg.as_ref())
                 .read(b"12345")
                 .build()
         };
         let mut buf = BytesMut::new();
-        let h = Header::read_prefaced(&mut rx, &mut buf)
+        let h = Header::read_prefaced(&mut rx, &mut buf, MESSAGE_LENGTH_MAX)
             .await
             .expect("I/O must not error")
             .expect("header must be present");
@@ -261,4 +535,172 @@ mod tests {
             .expect("I/O must still have data");
         assert_eq!(&buf, b"12345");
     }
+
+    #[tokio::test]
+    async fn frame_header_roundtrip() {
+        let header = FrameHeader {
+            length: 5,
+            stream_id: 42,
+            type_: frame_type::DATA,
+            flags: flags::REMOTE_OPEN,
+        };
+        let mut rx = {
+            let mut buf = BytesMut::new();
+            header.encode(&mut buf).expect("must encode");
+            buf.put_slice(b"12345");
+            std::io::Cursor::new(buf.freeze())
+        };
+        let mut buf = BytesMut::new();
+        let h = FrameHeader::read(&mut rx, &mut buf)
+            .await
+            .expect("must decode");
+        assert_eq!(header, h);
+        assert_eq!(buf.capacity() >= h.length as usize, true);
+    }
+
+    #[tokio::test]
+    async fn frame_header_rejects_oversized_length() {
+        let header = FrameHeader {
+            length: (MESSAGE_LENGTH_MAX + 1) as u32,
+            stream_id: 1,
+            type_: frame_type::DATA,
+            flags: 0,
+        };
+        let mut rx = {
+            let mut buf = BytesMut::new();
+            buf.put_u32(header.length);
+            buf.put_u32(header.stream_id);
+            buf.put_u8(header.type_);
+            buf.put_u8(header.flags);
+            std::io::Cursor::new(buf.freeze())
+        };
+        let mut buf = BytesMut::new();
+        let err = FrameHeader::read(&mut rx, &mut buf)
+            .await
+            .expect_err("must reject oversized frame");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn read_prefaced_rejects_non_request_frame() {
+        let mut rx = {
+            let mut buf = BytesMut::new();
+            buf.put_slice(PREFACE);
+            FrameHeader {
+                length: 0,
+                stream_id: 0,
+                type_: frame_type::DATA,
+                flags: 0,
+            }
+            .encode(&mut buf)
+            .expect("must encode");
+            std::io::Cursor::new(buf.freeze())
+        };
+        let mut buf = BytesMut::new();
+        let err = Header::read_prefaced(&mut rx, &mut buf, MESSAGE_LENGTH_MAX)
+            .await
+            .expect_err("must reject a non-REQUEST frame");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn read_prefaced_rejects_oversized_message() {
+        // A configured `max_message_len` well under `MESSAGE_LENGTH_MAX`
+        // must be enforced by `read_prefaced` itself, not just the frame
+        // layer's own cap.
+        const MAX_MESSAGE_LEN: usize = 16;
+
+        let mut rx = {
+            let mut buf = BytesMut::new();
+            buf.put_slice(PREFACE);
+            // Declare a message length that fits under MESSAGE_LENGTH_MAX
+            // but exceeds the configured max, without actually providing
+            // that much data.
+            FrameHeader {
+                length: (MAX_MESSAGE_LEN + 1) as u32,
+                stream_id: 0,
+                type_: frame_type::REQUEST,
+                flags: flags::REMOTE_OPEN,
+            }
+            .encode(&mut buf)
+            .expect("must encode");
+            std::io::Cursor::new(buf.freeze())
+        };
+        let mut buf = BytesMut::new();
+        let err = Header::read_prefaced(&mut rx, &mut buf, MAX_MESSAGE_LEN)
+            .await
+            .expect_err("must reject oversized header message");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn detect_protocol_connect() {
+        let header = Header {
+            port: 4040,
+            name: Some(Name::from_str("foo.bar.example.com").unwrap()),
+        };
+        let mut rx = {
+            let mut buf = BytesMut::new();
+            header.encode_prefaced(&mut buf).expect("must encode");
+            buf.put_slice(b"12345");
+            std::io::Cursor::new(buf.freeze())
+        };
+        let mut buf = BytesMut::new();
+        let p = DetectProtocol::default()
+            .detect(&mut rx, &mut buf)
+            .await
+            .expect("must not fail")
+            .expect("must detect a protocol");
+        match p {
+            Protocol::Connect(h) => {
+                assert_eq!(header.port, h.port);
+                assert_eq!(header.name, h.name);
+            }
+            _ => panic!("expected Protocol::Connect"),
+        }
+        assert_eq!(&buf[..], b"12345");
+    }
+
+    #[tokio::test]
+    async fn detect_protocol_http2() {
+        const H2: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        let (mut rx, _tx) = tokio_test::io::Builder::new().read(H2).build_with_handle();
+        let mut buf = BytesMut::new();
+        let p = DetectProtocol::default()
+            .detect(&mut rx, &mut buf)
+            .await
+            .expect("must not fail")
+            .expect("must detect a protocol");
+        assert!(matches!(p, Protocol::Http2));
+    }
+
+    #[tokio::test]
+    async fn detect_protocol_tls() {
+        const CLIENT_HELLO: &[u8] = &[0x16, 0x03, 0x01, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05];
+        let (mut rx, _tx) = tokio_test::io::Builder::new()
+            .read(CLIENT_HELLO)
+            .build_with_handle();
+        let mut buf = BytesMut::new();
+        let p = DetectProtocol::default()
+            .detect(&mut rx, &mut buf)
+            .await
+            .expect("must not fail")
+            .expect("must detect a protocol");
+        assert!(matches!(p, Protocol::Tls));
+        assert_eq!(&buf[..], CLIENT_HELLO);
+    }
+
+    #[tokio::test]
+    async fn detect_protocol_opaque() {
+        const MSG: &'static [u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (mut rx, _tx) = tokio_test::io::Builder::new().read(MSG).build_with_handle();
+        let mut buf = BytesMut::new();
+        let p = DetectProtocol::default()
+            .detect(&mut rx, &mut buf)
+            .await
+            .expect("must not fail")
+            .expect("must detect a protocol");
+        assert!(matches!(p, Protocol::Opaque));
+        assert_eq!(&buf[..], MSG);
+    }
 }
\ No newline at end of file
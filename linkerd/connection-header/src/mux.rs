@@ -0,0 +1,614 @@
+//! A stream multiplexer layered on the framed `proxy.l5d.io/connect`
+//! transport.
+//!
+//! A single connection can carry multiple independent logical streams,
+//! each keyed by the `stream_id` on every [`FrameHeader`]. `DATA` frames
+//! are demultiplexed to per-stream channels, and `REMOTE_CLOSED`/
+//! `REMOTE_OPEN` half-close/open each stream's direction.
+//!
+//! The read half is driven exclusively by [`Multiplexer::accept`]; the
+//! write half and stream registry are `Arc`-shared so [`Connector::open`]
+//! can originate substreams from other tasks without deadlocking on it.
+
+use crate::{flags, frame_type, FrameHeader, Header};
+use bytes::BytesMut;
+use linkerd2_io::{self as io, AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as SyncMutex};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{trace, warn};
+
+/// The number of data frames buffered per substream before the
+/// multiplexer stops reading frames from the connection.
+const SUBSTREAM_BUFFER_CAPACITY: usize = 32;
+
+/// The maximum number of concurrently live substreams.
+const MAX_LIVE_STREAMS: usize = 1024;
+
+/// Which end of a connection a [`Multiplexer`] is acting as.
+///
+/// Locally-opened substream IDs are even for `Server`, odd for `Client`
+/// (like HTTP/2), so the two ends of a connection never allocate the same
+/// `stream_id` for their own locally-opened substreams.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// The stream registry shared between a [`Multiplexer`]'s reader and any
+/// number of [`Connector`]s.
+///
+/// Only locked for brief, synchronous bookkeeping, never across an I/O
+/// await, so it's a plain (non-async) mutex.
+struct Streams {
+    by_id: HashMap<u32, mpsc::Sender<DataFrame>>,
+    next_stream_id: u32,
+}
+
+/// A chunk of payload delivered to a [`Substream`], tagged with the flags
+/// that accompanied it.
+struct DataFrame {
+    data: BytesMut,
+    flags: u8,
+}
+
+/// Demultiplexes a single framed `proxy.l5d.io/connect` transport into
+/// independent logical substreams, keyed by [`FrameHeader::stream_id`].
+///
+/// Call [`Multiplexer::accept`] in a loop to obtain newly opened
+/// [`Substream`]s.
+pub struct Multiplexer<I> {
+    read: tokio::io::ReadHalf<I>,
+    read_buf: BytesMut,
+    write: Arc<Mutex<tokio::io::WriteHalf<I>>>,
+    streams: Arc<SyncMutex<Streams>>,
+}
+
+/// A handle that can originate new outbound substreams on a
+/// [`Multiplexer`]'s transport, concurrently with [`Multiplexer::accept`].
+///
+/// Cheaply `Clone`.
+pub struct Connector<I> {
+    write: Arc<Mutex<tokio::io::WriteHalf<I>>>,
+    streams: Arc<SyncMutex<Streams>>,
+}
+
+impl<I> Clone for Connector<I> {
+    fn clone(&self) -> Self {
+        Self {
+            write: self.write.clone(),
+            streams: self.streams.clone(),
+        }
+    }
+}
+
+/// An independent logical stream multiplexed over a single
+/// `proxy.l5d.io/connect` transport.
+pub struct Substream<I> {
+    /// The target of this substream, as declared by its opening frame.
+    pub header: Header,
+
+    stream_id: u32,
+    write: Arc<Mutex<tokio::io::WriteHalf<I>>>,
+    streams: Arc<SyncMutex<Streams>>,
+    data_rx: mpsc::Receiver<DataFrame>,
+    remote_closed: bool,
+}
+
+impl<I> Multiplexer<I>
+where
+    I: io::AsyncRead + io::AsyncWrite + Send + Unpin + 'static,
+{
+    pub fn new(io: I, role: Role) -> Self {
+        let (read, write) = tokio::io::split(io);
+        let next_stream_id = match role {
+            Role::Server => 0,
+            Role::Client => 1,
+        };
+        Self {
+            read,
+            read_buf: BytesMut::new(),
+            write: Arc::new(Mutex::new(write)),
+            streams: Arc::new(SyncMutex::new(Streams {
+                by_id: HashMap::new(),
+                next_stream_id,
+            })),
+        }
+    }
+
+    /// Returns a [`Connector`] that can originate outbound substreams on
+    /// this transport from any task, concurrently with `accept`.
+    pub fn connector(&self) -> Connector<I> {
+        Connector {
+            write: self.write.clone(),
+            streams: self.streams.clone(),
+        }
+    }
+
+    /// Waits for a frame that opens a new substream: a `REQUEST` frame
+    /// bearing the `REMOTE_OPEN` flag on a `stream_id` that hasn't been
+    /// seen before.
+    ///
+    /// `DATA` frames for already-accepted substreams are demultiplexed to
+    /// their channel along the way; a slow substream's full channel
+    /// backpressures the whole connection.
+    ///
+    /// Takes `&mut self`: only one task may drive the read half at a time.
+    /// Use [`Multiplexer::connector`] to originate substreams elsewhere.
+    pub async fn accept(&mut self) -> io::Result<Substream<I>> {
+        loop {
+            let fh = Self::read_frame(&mut self.read, &mut self.read_buf).await?;
+
+            match fh.type_ {
+                frame_type::REQUEST if fh.flags & flags::REMOTE_OPEN != 0 => {
+                    let rx = {
+                        let mut streams = self.streams.lock().unwrap();
+                        if streams.by_id.contains_key(&fh.stream_id) {
+                            warn!(stream_id = fh.stream_id, "Ignoring duplicate stream open");
+                            None
+                        } else if streams.by_id.len() >= MAX_LIVE_STREAMS {
+                            // Reject just this stream rather than failing
+                            // the whole connection, so one misbehaving
+                            // peer can't tear down every other substream
+                            // sharing the transport.
+                            warn!(
+                                stream_id = fh.stream_id,
+                                "Rejecting stream open: too many live substreams"
+                            );
+                            None
+                        } else {
+                            let (tx, rx) = mpsc::channel(SUBSTREAM_BUFFER_CAPACITY);
+                            streams.by_id.insert(fh.stream_id, tx);
+                            Some(rx)
+                        }
+                    };
+
+                    let payload = self.read_buf.split_to(fh.length as usize);
+                    let rx = match rx {
+                        Some(rx) => rx,
+                        None => continue,
+                    };
+                    let header = Header::decode(payload.freeze())?.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Missing substream header")
+                    })?;
+
+                    return Ok(Substream {
+                        header,
+                        stream_id: fh.stream_id,
+                        write: self.write.clone(),
+                        streams: self.streams.clone(),
+                        data_rx: rx,
+                        remote_closed: fh.flags & flags::REMOTE_CLOSED != 0,
+                    });
+                }
+
+                frame_type::DATA => {
+                    let payload = self.read_buf.split_to(fh.length as usize);
+                    let tx = {
+                        let streams = self.streams.lock().unwrap();
+                        streams.by_id.get(&fh.stream_id).cloned()
+                    };
+                    if let Some(tx) = tx {
+                        let _ = tx
+                            .send(DataFrame {
+                                data: payload,
+                                flags: fh.flags,
+                            })
+                            .await;
+                    } else {
+                        trace!(stream_id = fh.stream_id, "Dropping frame for unknown stream");
+                    }
+                }
+
+                _ => {
+                    self.read_buf.split_to(fh.length as usize);
+                    trace!(
+                        type_ = fh.type_,
+                        stream_id = fh.stream_id,
+                        "Ignoring frame"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Reads a frame header, blocking until its full payload is buffered.
+    async fn read_frame(
+        read: &mut tokio::io::ReadHalf<I>,
+        read_buf: &mut BytesMut,
+    ) -> io::Result<FrameHeader> {
+        let fh = FrameHeader::read(read, read_buf).await?;
+        while read_buf.len() < fh.length as usize {
+            if read.read_buf(read_buf).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed mid-frame",
+                ));
+            }
+        }
+        Ok(fh)
+    }
+}
+
+impl<I> Connector<I>
+where
+    I: io::AsyncWrite + Unpin,
+{
+    /// Opens a new outbound substream, writing a `REQUEST` frame carrying
+    /// `header` on a freshly allocated `stream_id`.
+    pub async fn open(&self, header: Header) -> io::Result<Substream<I>> {
+        let mut msg = BytesMut::new();
+        header
+            .encode(&mut msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let (stream_id, rx) = {
+            let mut streams = self.streams.lock().unwrap();
+            if streams.by_id.len() >= MAX_LIVE_STREAMS {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Too many live substreams",
+                ));
+            }
+            let stream_id = streams.next_stream_id;
+            streams.next_stream_id = stream_id.wrapping_add(2);
+            let (tx, rx) = mpsc::channel(SUBSTREAM_BUFFER_CAPACITY);
+            streams.by_id.insert(stream_id, tx);
+            (stream_id, rx)
+        };
+
+        let fh = FrameHeader {
+            length: msg.len() as u32,
+            stream_id,
+            type_: frame_type::REQUEST,
+            flags: flags::REMOTE_OPEN,
+        };
+        let mut buf = BytesMut::with_capacity(msg.len() + 10);
+        if let Err(e) = fh.encode(&mut buf) {
+            self.streams.lock().unwrap().by_id.remove(&stream_id);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        buf.extend_from_slice(&msg);
+
+        if let Err(e) = self.write.lock().await.write_all(&buf).await {
+            self.streams.lock().unwrap().by_id.remove(&stream_id);
+            return Err(e);
+        }
+
+        Ok(Substream {
+            header,
+            stream_id,
+            write: self.write.clone(),
+            streams: self.streams.clone(),
+            data_rx: rx,
+            remote_closed: false,
+        })
+    }
+}
+
+impl<I> Substream<I>
+where
+    I: io::AsyncWrite + Unpin,
+{
+    /// Writes a `DATA` frame carrying `data` to this substream's peer.
+    pub async fn send_data(&self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(data, 0).await
+    }
+
+    /// Writes an empty `DATA` frame with `REMOTE_CLOSED` set, half-closing
+    /// this substream's outbound direction.
+    pub async fn close(&self) -> io::Result<()> {
+        self.write_frame(&[], flags::REMOTE_CLOSED | flags::NO_DATA)
+            .await
+    }
+
+    async fn write_frame(&self, data: &[u8], flags: u8) -> io::Result<()> {
+        let fh = FrameHeader {
+            length: data.len() as u32,
+            stream_id: self.stream_id,
+            type_: frame_type::DATA,
+            flags,
+        };
+
+        let mut buf = BytesMut::with_capacity(data.len());
+        fh.encode(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        buf.extend_from_slice(data);
+
+        self.write.lock().await.write_all(&buf).await
+    }
+}
+
+impl<I> Substream<I> {
+    /// Receives the next chunk of data for this substream, or `None` once
+    /// the peer has half-closed its outbound direction and no more data
+    /// remains buffered.
+    pub async fn recv_data(&mut self) -> Option<BytesMut> {
+        if self.remote_closed {
+            return None;
+        }
+        let frame = self.data_rx.recv().await?;
+        if frame.flags & flags::REMOTE_CLOSED != 0 {
+            self.remote_closed = true;
+        }
+        if frame.flags & flags::NO_DATA != 0 {
+            return if self.remote_closed {
+                None
+            } else {
+                Some(BytesMut::new())
+            };
+        }
+        Some(frame.data)
+    }
+}
+
+impl<I> Drop for Substream<I> {
+    fn drop(&mut self) {
+        // Free this stream's slot regardless of whether a half-close
+        // frame was ever sent, so the registry doesn't grow unbounded.
+        if let Ok(mut streams) = self.streams.lock() {
+            streams.by_id.remove(&self.stream_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn frame_bytes(type_: u8, stream_id: u32, flags: u8, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        FrameHeader {
+            length: payload.len() as u32,
+            stream_id,
+            type_,
+            flags,
+        }
+        .encode(&mut buf)
+        .expect("must encode");
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn request_bytes(stream_id: u32, header: &Header) -> BytesMut {
+        let mut msg = BytesMut::new();
+        header.encode(&mut msg).expect("must encode");
+        frame_bytes(frame_type::REQUEST, stream_id, flags::REMOTE_OPEN, &msg)
+    }
+
+    #[tokio::test]
+    async fn interleaved_substreams_demux_correctly() {
+        let h1 = Header {
+            port: 1,
+            name: Some(linkerd2_dns_name::Name::from_str("one.example.com").unwrap()),
+        };
+        let h2 = Header {
+            port: 2,
+            name: Some(linkerd2_dns_name::Name::from_str("two.example.com").unwrap()),
+        };
+
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&request_bytes(1, &h1));
+        bytes.extend_from_slice(&frame_bytes(frame_type::DATA, 1, 0, b"a"));
+        bytes.extend_from_slice(&request_bytes(2, &h2));
+        bytes.extend_from_slice(&frame_bytes(frame_type::DATA, 2, 0, b"b"));
+        bytes.extend_from_slice(&frame_bytes(frame_type::DATA, 1, 0, b"c"));
+
+        let io = tokio_test::io::Builder::new().read(&bytes).build();
+        let mut mux = Multiplexer::new(io, Role::Server);
+
+        let mut s1 = mux.accept().await.expect("accepts stream 1");
+        assert_eq!(s1.header.port, h1.port);
+
+        let mut s2 = mux.accept().await.expect("accepts stream 2");
+        assert_eq!(s2.header.port, h2.port);
+
+        // Only `accept` drives the read half: run it to EOF so the
+        // remaining `DATA` frames are demultiplexed.
+        mux.accept()
+            .await
+            .expect_err("no further stream opens follow in this byte sequence");
+
+        assert_eq!(s1.recv_data().await.as_deref(), Some(&b"a"[..]));
+        assert_eq!(s2.recv_data().await.as_deref(), Some(&b"b"[..]));
+        assert_eq!(s1.recv_data().await.as_deref(), Some(&b"c"[..]));
+    }
+
+    #[tokio::test]
+    async fn recv_data_handles_remote_closed() {
+        let header = Header {
+            port: 1,
+            name: None,
+        };
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&request_bytes(1, &header));
+        bytes.extend_from_slice(&frame_bytes(
+            frame_type::DATA,
+            1,
+            flags::NO_DATA | flags::REMOTE_CLOSED,
+            &[],
+        ));
+
+        let io = tokio_test::io::Builder::new().read(&bytes).build();
+        let mut mux = Multiplexer::new(io, Role::Server);
+        let mut s = mux.accept().await.expect("accepts stream");
+
+        // Only `accept` drives the read half: run it to EOF so the close
+        // frame is demultiplexed.
+        mux.accept()
+            .await
+            .expect_err("no further stream opens follow in this byte sequence");
+
+        assert_eq!(s.recv_data().await, None, "must observe remote close");
+        assert_eq!(
+            s.recv_data().await,
+            None,
+            "must keep reporting closed once observed"
+        );
+    }
+
+    #[tokio::test]
+    async fn backpressure_blocks_until_substream_drains() {
+        let header = Header {
+            port: 1,
+            name: None,
+        };
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&request_bytes(1, &header));
+        // One more DATA frame than the substream's channel can hold
+        // without a reader draining it.
+        for _ in 0..=SUBSTREAM_BUFFER_CAPACITY {
+            bytes.extend_from_slice(&frame_bytes(frame_type::DATA, 1, 0, b"x"));
+        }
+        // A second stream's open frame, only reachable once the backlog
+        // above has been drained.
+        let header2 = Header {
+            port: 2,
+            name: None,
+        };
+        bytes.extend_from_slice(&request_bytes(2, &header2));
+
+        let io = tokio_test::io::Builder::new().read(&bytes).build();
+        let mut mux = Multiplexer::new(io, Role::Server);
+        let mut s1 = mux.accept().await.expect("accepts stream 1");
+
+        // Nothing has drained `s1` yet, so the reader fills its channel
+        // and then blocks trying to enqueue the final `DATA` frame,
+        // meaning it can't reach stream 2's `REQUEST` frame yet.
+        use futures::FutureExt;
+        let mut accept_fut = Box::pin(mux.accept());
+        assert!(
+            (&mut accept_fut).now_or_never().is_none(),
+            "accept() must block on the full substream buffer"
+        );
+
+        // Draining frees room in the channel, letting the reader make
+        // progress to stream 2's open frame.
+        for _ in 0..SUBSTREAM_BUFFER_CAPACITY {
+            assert_eq!(s1.recv_data().await.as_deref(), Some(&b"x"[..]));
+        }
+        let s2 = accept_fut.await.expect("accepts stream 2 once drained");
+        assert_eq!(s2.header.port, 2);
+
+        // The frame the reader was blocked on was enqueued once it resumed.
+        assert_eq!(s1.recv_data().await.as_deref(), Some(&b"x"[..]));
+    }
+
+    #[tokio::test]
+    async fn connector_open_rejects_once_max_live_streams_reached() {
+        // Use a writer-only connector against a sink, since this only
+        // exercises the live-stream cap, not frame parsing.
+        let (a, _b) = tokio::io::duplex(64 * 1024);
+        let mux = Multiplexer::new(a, Role::Client);
+        let connector = mux.connector();
+
+        // Keep every substream alive: `Substream`'s `Drop` frees its slot,
+        // so a temporary that's dropped immediately would never fill the
+        // registry up to the cap.
+        let mut live = Vec::with_capacity(MAX_LIVE_STREAMS);
+        for i in 0..MAX_LIVE_STREAMS {
+            let s = connector
+                .open(Header {
+                    port: i as u16,
+                    name: None,
+                })
+                .await
+                .expect("must open substream under the cap");
+            live.push(s);
+        }
+
+        let err = connector
+            .open(Header {
+                port: 0,
+                name: None,
+            })
+            .await
+            .expect_err("must reject once MAX_LIVE_STREAMS is reached");
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[tokio::test]
+    async fn accept_skips_rejected_stream_without_erroring_the_connection() {
+        // A stream open that arrives once the registry is already at
+        // MAX_LIVE_STREAMS, followed by a DATA frame for a pre-existing
+        // substream. The latter must still be delivered: hitting the cap
+        // must not fail the whole connection and drop every other stream.
+        let (tx, mut rx) = mpsc::channel(SUBSTREAM_BUFFER_CAPACITY);
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&request_bytes(
+            u32::MAX,
+            &Header {
+                port: 1,
+                name: None,
+            },
+        ));
+        bytes.extend_from_slice(&frame_bytes(frame_type::DATA, 1, 0, b"x"));
+
+        let io = tokio_test::io::Builder::new().read(&bytes).build();
+        let mut mux = Multiplexer::new(io, Role::Server);
+        {
+            let mut streams = mux.streams.lock().unwrap();
+            streams.by_id.insert(1, tx);
+            for id in 2..=MAX_LIVE_STREAMS as u32 {
+                streams.by_id.insert(id, mpsc::channel(1).0);
+            }
+        }
+
+        let err = mux
+            .accept()
+            .await
+            .expect_err("no further stream opens follow in this byte sequence");
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof,
+            "must process the rejected open and the DATA frame before hitting real EOF"
+        );
+
+        let frame = rx
+            .try_recv()
+            .expect("the DATA frame for the pre-existing stream must still be delivered");
+        assert_eq!(&frame.data[..], b"x");
+    }
+
+    #[tokio::test]
+    async fn locally_opened_streams_dont_collide_across_roles() {
+        let (a, _b) = tokio::io::duplex(64 * 1024);
+        let client = Multiplexer::new(a, Role::Client).connector();
+        let (b, _c) = tokio::io::duplex(64 * 1024);
+        let server = Multiplexer::new(b, Role::Server).connector();
+
+        let header = Header {
+            port: 1,
+            name: None,
+        };
+        let from_client = client.open(header.clone()).await.expect("must open");
+        let from_server = server.open(header).await.expect("must open");
+
+        assert_eq!(from_client.stream_id % 2, 1, "client streams are odd");
+        assert_eq!(from_server.stream_id % 2, 0, "server streams are even");
+    }
+
+    #[tokio::test]
+    async fn dropping_a_substream_frees_its_slot() {
+        let (a, _b) = tokio::io::duplex(64 * 1024);
+        let mux = Multiplexer::new(a, Role::Client);
+        let connector = mux.connector();
+
+        let s = connector
+            .open(Header {
+                port: 1,
+                name: None,
+            })
+            .await
+            .expect("must open");
+        drop(s);
+
+        assert_eq!(
+            mux.streams.lock().unwrap().by_id.len(),
+            0,
+            "dropping the substream must remove its registry entry"
+        );
+    }
+}